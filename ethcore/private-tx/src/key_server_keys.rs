@@ -17,26 +17,90 @@
 //! Wrapper around key server responsible for access keys processing.
 
 use std::sync::Arc;
-use parking_lot::RwLock;
+use std::collections::HashMap;
+use parking_lot::{Mutex, RwLock};
+use lru_cache::LruCache;
+use bytes::Bytes;
 use ethereum_types::{H256, Address};
-use ethcore::client::{BlockId, CallContract, Client, RegistryInfo};
+use ethcore::client::{BlockId, BlockChainClient, CallContract, ChainNotify, Client, NewBlocks, RegistryInfo};
 use ethabi::FunctionOutputDecoder;
 
 const ACL_CHECKER_CONTRACT_REGISTRY_NAME: &'static str = "secretstore_acl_checker";
 
+/// Default number of `(block hash, account)` -> `available keys` entries kept in memory
+const DEFAULT_AVAILABLE_KEYS_CACHE_ITEMS: usize = 1024;
+
 use_contract!(keys_acl_contract, "res/keys_acl.json");
 
-/// Returns the address (of the contract), that corresponds to the key
+/// Subset of chain access required by `SecretStoreKeys`, extracted so it can be mocked in tests
+/// and so that ACL lookups can be skipped while the node is still syncing.
+pub trait SecretStoreChain: Send + Sync + 'static {
+	/// Calls the contract at `address` with the given `data` at `block`
+	fn call_contract(&self, block: BlockId, address: Address, data: Bytes) -> Result<Bytes, String>;
+
+	/// Resolves the address of a contract registered under `name`
+	fn registry_address(&self, name: &str, block: BlockId) -> Option<Address>;
+
+	/// Returns `true` while the node is still performing a major sync, during which the chain
+	/// state is not trustworthy enough to answer ACL queries
+	fn is_major_syncing(&self) -> bool;
+}
+
+impl SecretStoreChain for Client {
+	fn call_contract(&self, block: BlockId, address: Address, data: Bytes) -> Result<Bytes, String> {
+		CallContract::call_contract(self, block, address, data)
+	}
+
+	fn registry_address(&self, name: &str, block: BlockId) -> Option<Address> {
+		RegistryInfo::registry_address(self, name.into(), block)
+	}
+
+	fn is_major_syncing(&self) -> bool {
+		BlockChainClient::is_major_syncing(self)
+	}
+}
+
+/// Scheme for deriving between secret store document keys (32 bytes) and the addresses the ACL
+/// contract operates on (20 bytes), guaranteed to round-trip: `address_to_key` followed by
+/// `key_to_address` always yields back the original address.
+pub trait KeyIdScheme: Send + Sync + 'static {
+	/// Returns the address (of the contract), that corresponds to the key
+	fn key_to_address(&self, key: &H256) -> Address;
+
+	/// Returns the key from the key server associated with the contract
+	fn address_to_key(&self, contract_address: &Address) -> H256;
+}
+
+/// Default key id scheme: takes the 20 low bytes of the key and zero-extends on the way back,
+/// matching the secret-store encryptor's `address_to_key` convention.
+#[derive(Default)]
+pub struct DefaultKeyIdScheme;
+
+impl KeyIdScheme for DefaultKeyIdScheme {
+	fn key_to_address(&self, key: &H256) -> Address {
+		Address::from_slice(&key[12..])
+	}
+
+	fn address_to_key(&self, contract_address: &Address) -> H256 {
+		// Current solution uses contract address extended with 0 as id
+		let contract_address_extended: H256 = contract_address.into();
+
+		H256::from_slice(&contract_address_extended)
+	}
+}
+
+/// Returns the address (of the contract), that corresponds to the key, using the default
+/// key/address derivation scheme. Kept for callers (e.g. the secret-store encryptor) that still
+/// call this directly rather than going through a `KeyIdScheme`.
 pub fn key_to_address(key: &H256) -> Address {
-	Address::from_slice(&key.to_vec()[..10])
+	DefaultKeyIdScheme.key_to_address(key)
 }
 
-/// Returns the key from the key server associated with the contract
+/// Returns the key from the key server associated with the contract, using the default
+/// key/address derivation scheme. Kept for callers (e.g. the secret-store encryptor) that still
+/// call this directly rather than going through a `KeyIdScheme`.
 pub fn address_to_key(contract_address: &Address) -> H256 {
-	// Current solution uses contract address extended with 0 as id
-	let contract_address_extended: H256 = contract_address.into();
-
-	H256::from_slice(&contract_address_extended)
+	DefaultKeyIdScheme.address_to_key(contract_address)
 }
 
 /// Trait for keys server keys provider.
@@ -47,24 +111,69 @@ pub trait KeyProvider: Send + Sync + 'static {
 	/// List of keys available for the account
 	fn available_keys(&self, block: BlockId, account: &Address) -> Option<Vec<Address>>;
 
+	/// Checks permissions for the account to access the given document's key
+	fn check_permissions(&self, block: BlockId, account: &Address, document: H256) -> Option<bool>;
+
 	/// Update permissioning contract
 	fn update_acl_contract(&self);
 }
 
+/// Source of the address of the ACL checker contract, mirroring how the secret store's own
+/// service contract can be configured as `none`/`registry`/`address`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContractSource {
+	/// ACL checks are disabled - `available_keys`/`check_permissions` always return `None`
+	None,
+	/// Resolve the contract address through the chain registry on every block
+	Registry,
+	/// Use a fixed, pre-configured contract address
+	Address(Address),
+}
+
 /// Secret Store keys provider
 pub struct SecretStoreKeys {
-	client: Arc<Client>,
+	chain: Arc<dyn SecretStoreChain>,
 	key_server_account: Option<Address>,
+	contract_source: ContractSource,
 	keys_acl_contract: RwLock<Option<Address>>,
+	/// Hash of the chain head the cached `keys_acl_contract`/`available_keys` were resolved at
+	best_block_hash: RwLock<Option<H256>>,
+	available_keys_cache: Mutex<LruCache<(H256, Address), Vec<Address>>>,
+	key_id_scheme: Arc<dyn KeyIdScheme>,
 }
 
 impl SecretStoreKeys {
 	/// Create provider
-	pub fn new(client: Arc<Client>, key_server_account: Option<Address>) -> Self {
+	pub fn new(chain: Arc<dyn SecretStoreChain>, key_server_account: Option<Address>, contract_source: ContractSource) -> Self {
+		Self::with_cache_size(chain, key_server_account, contract_source, DEFAULT_AVAILABLE_KEYS_CACHE_ITEMS)
+	}
+
+	/// Create provider with a custom `available_keys` cache size
+	pub fn with_cache_size(chain: Arc<dyn SecretStoreChain>, key_server_account: Option<Address>, contract_source: ContractSource, cache_size: usize) -> Self {
+		Self::with_key_id_scheme(chain, key_server_account, contract_source, cache_size, Arc::new(DefaultKeyIdScheme))
+	}
+
+	/// Create provider with a custom `available_keys` cache size and key/address derivation scheme
+	pub fn with_key_id_scheme(
+		chain: Arc<dyn SecretStoreChain>,
+		key_server_account: Option<Address>,
+		contract_source: ContractSource,
+		cache_size: usize,
+		key_id_scheme: Arc<dyn KeyIdScheme>,
+	) -> Self {
+		let keys_acl_contract = match contract_source {
+			ContractSource::Address(address) => Some(address),
+			ContractSource::None | ContractSource::Registry => None,
+		};
+
 		SecretStoreKeys {
-			client,
+			chain,
 			key_server_account,
-			keys_acl_contract: RwLock::new(None),
+			contract_source,
+			keys_acl_contract: RwLock::new(keys_acl_contract),
+			best_block_hash: RwLock::new(None),
+			available_keys_cache: Mutex::new(LruCache::new(cache_size)),
+			key_id_scheme,
 		}
 	}
 
@@ -72,7 +181,7 @@ impl SecretStoreKeys {
 		keys.map(|key_values| {
 			let mut addresses: Vec<Address> = Vec::new();
 			for key in key_values {
-				addresses.push(key_to_address(&key));
+				addresses.push(self.key_id_scheme.key_to_address(&key));
 			}
 			addresses
 		})
@@ -85,30 +194,98 @@ impl KeyProvider for SecretStoreKeys {
 	}
 
 	fn available_keys(&self, block: BlockId, account: &Address) -> Option<Vec<Address>> {
-		match *self.keys_acl_contract.read() {
+		if self.chain.is_major_syncing() {
+			return None;
+		}
+
+		// The cache is keyed by the chain head hash, so it's only valid for queries against the
+		// head itself - a historical `BlockId::Number`/`BlockId::Hash` lookup must bypass it,
+		// otherwise it could be served a head-cached result or pollute the cache with a result
+		// that was actually fetched at a different block.
+		let cacheable = block == BlockId::Latest;
+
+		if cacheable {
+			if let Some(best_block_hash) = *self.best_block_hash.read() {
+				if let Some(cached) = self.available_keys_cache.lock().get_mut(&(best_block_hash, *account)) {
+					return Some(cached.clone());
+				}
+			}
+		}
+
+		let result = match *self.keys_acl_contract.read() {
 			Some(acl_contract_address) => {
 				let (data, decoder) = keys_acl_contract::functions::available_keys::call(*account);
-				if let Ok(value) = self.client.call_contract(block, acl_contract_address, data) {
+				if let Ok(value) = self.chain.call_contract(block, acl_contract_address, data) {
 					self.keys_to_addresses(decoder.decode(&value).ok())
 				} else {
 					None
 				}
 			}
 			None => None,
+		};
+
+		if cacheable {
+			if let (Some(best_block_hash), Some(ref keys)) = (*self.best_block_hash.read(), &result) {
+				self.available_keys_cache.lock().insert((best_block_hash, *account), keys.clone());
+			}
+		}
+
+		result
+	}
+
+	fn check_permissions(&self, block: BlockId, account: &Address, document: H256) -> Option<bool> {
+		if self.chain.is_major_syncing() {
+			return None;
+		}
+
+		match *self.keys_acl_contract.read() {
+			Some(acl_contract_address) => {
+				let (data, decoder) = keys_acl_contract::functions::check_permissions::call(*account, self.key_id_scheme.key_to_address(&document));
+				self.chain.call_contract(block, acl_contract_address, data).ok()
+					.and_then(|value| decoder.decode(&value).ok())
+			}
+			None => None,
 		}
 	}
 
 	fn update_acl_contract(&self) {
-		let contract_address = self.client.registry_address(ACL_CHECKER_CONTRACT_REGISTRY_NAME.into(), BlockId::Latest);
-		let current_address = self.keys_acl_contract.read();
+		if self.contract_source != ContractSource::Registry {
+			// `None` disables ACL checks entirely, `Address` is pinned once at construction time
+			return;
+		}
+
+		if self.chain.is_major_syncing() {
+			return;
+		}
 
-		if *current_address != contract_address {
+		let contract_address = self.chain.registry_address(ACL_CHECKER_CONTRACT_REGISTRY_NAME, BlockId::Latest);
+		if *self.keys_acl_contract.read() != contract_address {
 			trace!(target: "privatetx", "Configuring for ACL checker contract from address {:?}",
 				contract_address);
 
-			let keys_acl_contract = self.keys_acl_contract.write();
-			keys_acl_contract.and(contract_address);
+			*self.keys_acl_contract.write() = contract_address;
+		}
+	}
+}
+
+impl ChainNotify for SecretStoreKeys {
+	fn new_blocks(&self, new_blocks: NewBlocks) {
+		if new_blocks.imported.is_empty() {
+			return;
 		}
+
+		let new_best_block_hash = match new_blocks.imported.last() {
+			Some(hash) => *hash,
+			None => return,
+		};
+
+		if *self.best_block_hash.read() == Some(new_best_block_hash) {
+			return;
+		}
+
+		*self.best_block_hash.write() = Some(new_best_block_hash);
+		self.available_keys_cache.lock().clear();
+		self.update_acl_contract();
 	}
 }
 
@@ -116,12 +293,18 @@ impl KeyProvider for SecretStoreKeys {
 #[derive(Default)]
 pub struct StoringKeyProvider {
 	available_keys: Option<Vec<Address>>,
+	permissions: HashMap<H256, bool>,
 }
 
 impl StoringKeyProvider {
 	fn set_available_keys(&mut self, keys: &Vec<Address>) {
 		self.available_keys.replace(keys.to_vec());
 	}
+
+	/// Sets the permission that will be returned by `check_permissions` for the given document
+	pub fn set_permission(&mut self, document: H256, permitted: bool) {
+		self.permissions.insert(document, permitted);
+	}
 }
 
 impl KeyProvider for StoringKeyProvider {
@@ -131,5 +314,216 @@ impl KeyProvider for StoringKeyProvider {
 		self.available_keys.clone()
 	}
 
+	fn check_permissions(&self, _block: BlockId, _account: &Address, document: H256) -> Option<bool> {
+		self.permissions.get(&document).cloned()
+	}
+
 	fn update_acl_contract(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::collections::HashMap;
+	use parking_lot::Mutex;
+	use ethabi::Token;
+	use ethereum_types::{H256, Address};
+	use ethcore::client::{BlockId, ChainNotify, NewBlocks};
+	use super::{KeyIdScheme, DefaultKeyIdScheme, KeyProvider, SecretStoreChain, SecretStoreKeys, ContractSource, StoringKeyProvider, keys_acl_contract};
+
+	#[test]
+	fn default_scheme_round_trips_addresses() {
+		let scheme = DefaultKeyIdScheme;
+		let addresses = vec![
+			Address::from_low_u64_be(0),
+			Address::from_low_u64_be(1),
+			Address::from_low_u64_be(0xffff_ffff),
+			Address::from_slice(&[0xff; 20]),
+		];
+
+		for address in addresses {
+			let key = scheme.address_to_key(&address);
+			assert_eq!(scheme.key_to_address(&key), address);
+		}
+	}
+
+	/// A `SecretStoreChain` whose `call_contract`/`registry_address` results are configured by
+	/// the test, so `SecretStoreKeys` can be exercised without a real `Client`.
+	#[derive(Default)]
+	struct MockChain {
+		registry_address: Mutex<Option<Address>>,
+		registry_calls: Mutex<usize>,
+		major_syncing: Mutex<bool>,
+		call_results: Mutex<HashMap<(Address, Vec<u8>), Vec<u8>>>,
+		call_count: Mutex<usize>,
+	}
+
+	impl MockChain {
+		fn new() -> Self {
+			MockChain::default()
+		}
+
+		fn set_registry_address(&self, address: Address) {
+			*self.registry_address.lock() = Some(address);
+		}
+
+		fn set_major_syncing(&self, syncing: bool) {
+			*self.major_syncing.lock() = syncing;
+		}
+
+		fn set_call_result(&self, address: Address, data: Vec<u8>, result: Vec<u8>) {
+			self.call_results.lock().insert((address, data), result);
+		}
+
+		fn call_count(&self) -> usize {
+			*self.call_count.lock()
+		}
+
+		fn registry_calls(&self) -> usize {
+			*self.registry_calls.lock()
+		}
+	}
+
+	impl SecretStoreChain for MockChain {
+		fn call_contract(&self, _block: BlockId, address: Address, data: Vec<u8>) -> Result<Vec<u8>, String> {
+			*self.call_count.lock() += 1;
+			self.call_results.lock().get(&(address, data)).cloned()
+				.ok_or_else(|| "no mock result configured for this call".into())
+		}
+
+		fn registry_address(&self, _name: &str, _block: BlockId) -> Option<Address> {
+			*self.registry_calls.lock() += 1;
+			*self.registry_address.lock()
+		}
+
+		fn is_major_syncing(&self) -> bool {
+			*self.major_syncing.lock()
+		}
+	}
+
+	fn encode_addresses(keys: &[H256]) -> Vec<u8> {
+		ethabi::encode(&[Token::Array(keys.iter().map(|k| Token::FixedBytes(k.as_bytes().to_vec())).collect())])
+	}
+
+	fn encode_bool(value: bool) -> Vec<u8> {
+		ethabi::encode(&[Token::Bool(value)])
+	}
+
+	fn imported_block(hash: H256) -> NewBlocks {
+		NewBlocks { imported: vec![hash], ..Default::default() }
+	}
+
+	#[test]
+	fn storing_key_provider_returns_configured_permission() {
+		let mut provider = StoringKeyProvider::default();
+		let document = H256::from_low_u64_be(1);
+		let account = Address::from_low_u64_be(1);
+
+		assert_eq!(provider.check_permissions(BlockId::Latest, &account, document), None);
+
+		provider.set_permission(document, true);
+		assert_eq!(provider.check_permissions(BlockId::Latest, &account, document), Some(true));
+
+		provider.set_permission(document, false);
+		assert_eq!(provider.check_permissions(BlockId::Latest, &account, document), Some(false));
+	}
+
+	#[test]
+	fn secret_store_keys_check_permissions_decodes_contract_result() {
+		let chain = Arc::new(MockChain::new());
+		let contract_address = Address::from_low_u64_be(0xaced);
+		let account = Address::from_low_u64_be(1);
+		let document = H256::from_low_u64_be(42);
+
+		let (data, _) = keys_acl_contract::functions::check_permissions::call(account, DefaultKeyIdScheme.key_to_address(&document));
+		chain.set_call_result(contract_address, data, encode_bool(true));
+
+		let provider = SecretStoreKeys::new(chain, None, ContractSource::Address(contract_address));
+		assert_eq!(provider.check_permissions(BlockId::Latest, &account, document), Some(true));
+	}
+
+	#[test]
+	fn available_keys_caches_repeated_latest_queries() {
+		let chain = Arc::new(MockChain::new());
+		let contract_address = Address::from_low_u64_be(0xaced);
+		let account = Address::from_low_u64_be(1);
+		let key = H256::from_low_u64_be(7);
+
+		let (data, _) = keys_acl_contract::functions::available_keys::call(account);
+		chain.set_call_result(contract_address, data, encode_addresses(&[key]));
+
+		let provider = SecretStoreKeys::new(chain.clone(), None, ContractSource::Address(contract_address));
+		provider.new_blocks(imported_block(H256::from_low_u64_be(1)));
+
+		let first = provider.available_keys(BlockId::Latest, &account);
+		let second = provider.available_keys(BlockId::Latest, &account);
+
+		assert!(first.is_some());
+		assert_eq!(first, second);
+		assert_eq!(chain.call_count(), 1, "second Latest query should be served from cache");
+	}
+
+	#[test]
+	fn available_keys_bypasses_cache_for_non_latest_block() {
+		let chain = Arc::new(MockChain::new());
+		let contract_address = Address::from_low_u64_be(0xaced);
+		let account = Address::from_low_u64_be(1);
+		let key = H256::from_low_u64_be(7);
+
+		let (data, _) = keys_acl_contract::functions::available_keys::call(account);
+		chain.set_call_result(contract_address, data, encode_addresses(&[key]));
+
+		let provider = SecretStoreKeys::new(chain.clone(), None, ContractSource::Address(contract_address));
+		provider.new_blocks(imported_block(H256::from_low_u64_be(1)));
+
+		let historical_block = BlockId::Number(1);
+		provider.available_keys(historical_block, &account);
+		provider.available_keys(historical_block, &account);
+
+		assert_eq!(chain.call_count(), 2, "queries for a non-Latest block must never be cached");
+	}
+
+	#[test]
+	fn new_blocks_invalidates_available_keys_cache() {
+		let chain = Arc::new(MockChain::new());
+		let contract_address = Address::from_low_u64_be(0xaced);
+		let account = Address::from_low_u64_be(1);
+		let key = H256::from_low_u64_be(7);
+
+		let (data, _) = keys_acl_contract::functions::available_keys::call(account);
+		chain.set_call_result(contract_address, data, encode_addresses(&[key]));
+
+		let provider = SecretStoreKeys::new(chain.clone(), None, ContractSource::Address(contract_address));
+		provider.new_blocks(imported_block(H256::from_low_u64_be(1)));
+		provider.available_keys(BlockId::Latest, &account);
+
+		provider.new_blocks(imported_block(H256::from_low_u64_be(2)));
+		provider.available_keys(BlockId::Latest, &account);
+
+		assert_eq!(chain.call_count(), 2, "a new chain head must invalidate the previous cache entry");
+	}
+
+	#[test]
+	fn update_acl_contract_is_noop_unless_registry_source() {
+		let fixed_address = Address::from_low_u64_be(0xaced);
+
+		let chain = Arc::new(MockChain::new());
+		chain.set_registry_address(Address::from_low_u64_be(0xdead));
+		let fixed_provider = SecretStoreKeys::new(chain.clone(), None, ContractSource::Address(fixed_address));
+		fixed_provider.update_acl_contract();
+		assert_eq!(chain.registry_calls(), 0, "a fixed contract address must never consult the registry");
+		assert_eq!(fixed_provider.available_keys(BlockId::Latest, &Address::zero()), None);
+
+		let chain = Arc::new(MockChain::new());
+		chain.set_registry_address(Address::from_low_u64_be(0xdead));
+		let disabled_provider = SecretStoreKeys::new(chain.clone(), None, ContractSource::None);
+		disabled_provider.update_acl_contract();
+		assert_eq!(chain.registry_calls(), 0, "ACL checks must stay disabled for ContractSource::None");
+
+		let chain = Arc::new(MockChain::new());
+		chain.set_registry_address(Address::from_low_u64_be(0xdead));
+		let registry_provider = SecretStoreKeys::new(chain.clone(), None, ContractSource::Registry);
+		registry_provider.update_acl_contract();
+		assert_eq!(chain.registry_calls(), 1, "ContractSource::Registry must resolve the address through the registry");
+	}
 }
\ No newline at end of file
@@ -0,0 +1,299 @@
+// Copyright 2015-2018 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! On-chain discovery of the secret store key server cluster membership.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use parking_lot::RwLock;
+use ethereum_types::{H256, Address};
+use ethcore::client::{BlockId, ChainNotify, NewBlocks};
+use ethabi::FunctionOutputDecoder;
+use ethkey::Public;
+
+use key_server_keys::SecretStoreChain;
+
+const KEY_SERVER_SET_CONTRACT_REGISTRY_NAME: &'static str = "secretstore_server_set";
+
+use_contract!(key_server_set_contract, "res/key_server_set.json");
+
+/// Provides the current key server cluster membership, as discovered from the chain.
+pub trait KeyServerProvider: Send + Sync + 'static {
+	/// Returns the nodes of the cluster that the local node should connect to, i.e. the current
+	/// membership set with the local node (if a member) excluded.
+	fn key_servers(&self) -> BTreeMap<Public, SocketAddr>;
+
+	/// Returns `true` if the local node is a member of the current key server set.
+	fn is_local_node_member(&self) -> bool;
+}
+
+/// On-chain key server set, resolved through a registry-registered contract.
+pub struct OnChainKeyServerSet {
+	chain: Arc<dyn SecretStoreChain>,
+	self_public: Option<Public>,
+	contract_addr: RwLock<Option<Address>>,
+	best_block_hash: RwLock<Option<H256>>,
+	key_servers: RwLock<BTreeMap<Public, SocketAddr>>,
+}
+
+impl OnChainKeyServerSet {
+	/// Create a new key server set provider.
+	///
+	/// `self_public` identifies the local node so that it can be excluded from `key_servers()`.
+	pub fn new(chain: Arc<dyn SecretStoreChain>, self_public: Option<Public>) -> Self {
+		OnChainKeyServerSet {
+			chain,
+			self_public,
+			contract_addr: RwLock::new(None),
+			best_block_hash: RwLock::new(None),
+			key_servers: RwLock::new(BTreeMap::new()),
+		}
+	}
+
+	fn update_contract_address(&self) {
+		if self.chain.is_major_syncing() {
+			return;
+		}
+
+		let contract_addr = self.chain.registry_address(KEY_SERVER_SET_CONTRACT_REGISTRY_NAME, BlockId::Latest);
+		if *self.contract_addr.read() != contract_addr {
+			trace!(target: "privatetx", "Configuring for key server set contract from address {:?}", contract_addr);
+			*self.contract_addr.write() = contract_addr;
+		}
+	}
+
+	fn read_key_servers(&self) -> BTreeMap<Public, SocketAddr> {
+		let contract_addr = match *self.contract_addr.read() {
+			Some(contract_addr) => contract_addr,
+			None => return BTreeMap::new(),
+		};
+
+		let (data, decoder) = key_server_set_contract::functions::get_key_servers::call();
+		let ids = match self.chain.call_contract(BlockId::Latest, contract_addr, data)
+			.and_then(|value| decoder.decode(&value).map_err(|e| e.to_string())) {
+			Ok(ids) => ids,
+			Err(error) => {
+				trace!(target: "privatetx", "Error reading key server set contract: {}", error);
+				return BTreeMap::new();
+			}
+		};
+
+		let mut key_servers = BTreeMap::new();
+		for id in ids {
+			match self.read_key_server(contract_addr, id) {
+				Some((public, addr)) => { key_servers.insert(public, addr); }
+				None => trace!(target: "privatetx", "Skipping malformed key server entry for {:?}", id),
+			}
+		}
+
+		key_servers
+	}
+
+	fn read_key_server(&self, contract_addr: Address, id: Address) -> Option<(Public, SocketAddr)> {
+		let (data, decoder) = key_server_set_contract::functions::get_key_server_public::call(id);
+		let public_bytes = self.chain.call_contract(BlockId::Latest, contract_addr, data).ok()
+			.and_then(|value| decoder.decode(&value).ok())?;
+		// `Public` (H512) is a fixed-size 64 byte key - `from_slice` panics on any other length, so
+		// a misconfigured contract entry must be skipped here rather than trusted.
+		const PUBLIC_KEY_LEN: usize = 64;
+		if public_bytes.len() != PUBLIC_KEY_LEN {
+			trace!(target: "privatetx", "Key server {:?} has a malformed public key of length {}", id, public_bytes.len());
+			return None;
+		}
+		let public = Public::from_slice(&public_bytes);
+
+		let (data, decoder) = key_server_set_contract::functions::get_key_server_address::call(id);
+		let ip_addr = self.chain.call_contract(BlockId::Latest, contract_addr, data).ok()
+			.and_then(|value| decoder.decode(&value).ok())?;
+		let socket_addr = SocketAddr::from_str(&ip_addr).ok()?;
+
+		Some((public, socket_addr))
+	}
+}
+
+impl KeyServerProvider for OnChainKeyServerSet {
+	fn key_servers(&self) -> BTreeMap<Public, SocketAddr> {
+		let mut key_servers = self.key_servers.read().clone();
+		if let Some(ref self_public) = self.self_public {
+			// never connect to ourselves
+			key_servers.remove(self_public);
+		}
+		key_servers
+	}
+
+	fn is_local_node_member(&self) -> bool {
+		match self.self_public {
+			Some(ref self_public) => self.key_servers.read().contains_key(self_public),
+			None => false,
+		}
+	}
+}
+
+impl ChainNotify for OnChainKeyServerSet {
+	fn new_blocks(&self, new_blocks: NewBlocks) {
+		if new_blocks.imported.is_empty() {
+			return;
+		}
+
+		if self.chain.is_major_syncing() {
+			return;
+		}
+
+		let new_best_block_hash = match new_blocks.imported.last() {
+			Some(hash) => *hash,
+			None => return,
+		};
+
+		if *self.best_block_hash.read() == Some(new_best_block_hash) {
+			return;
+		}
+
+		*self.best_block_hash.write() = Some(new_best_block_hash);
+		self.update_contract_address();
+		*self.key_servers.write() = self.read_key_servers();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::sync::Arc;
+	use parking_lot::Mutex;
+	use ethabi::Token;
+	use ethereum_types::Address;
+	use ethcore::client::BlockId;
+	use ethkey::Public;
+	use super::{OnChainKeyServerSet, KeyServerProvider, SecretStoreChain};
+
+	/// A `SecretStoreChain` whose `call_contract` results are configured by the test.
+	#[derive(Default)]
+	struct MockChain {
+		call_results: Mutex<HashMap<(Address, Vec<u8>), Vec<u8>>>,
+	}
+
+	impl MockChain {
+		fn new() -> Self {
+			MockChain::default()
+		}
+
+		fn set_call_result(&self, address: Address, data: Vec<u8>, result: Vec<u8>) {
+			self.call_results.lock().insert((address, data), result);
+		}
+	}
+
+	impl SecretStoreChain for MockChain {
+		fn call_contract(&self, _block: BlockId, address: Address, data: Vec<u8>) -> Result<Vec<u8>, String> {
+			self.call_results.lock().get(&(address, data)).cloned()
+				.ok_or_else(|| "no mock result configured for this call".into())
+		}
+
+		fn registry_address(&self, _name: &str, _block: BlockId) -> Option<Address> {
+			None
+		}
+
+		fn is_major_syncing(&self) -> bool {
+			false
+		}
+	}
+
+	fn key_server_set(self_public: Option<Public>) -> OnChainKeyServerSet {
+		OnChainKeyServerSet::new(Arc::new(MockChain::new()), self_public)
+	}
+
+	#[test]
+	fn key_servers_never_includes_the_local_node() {
+		let self_public = Public::from_slice(&[1u8; 64]);
+		let other_public = Public::from_slice(&[2u8; 64]);
+		let set = key_server_set(Some(self_public));
+
+		*set.key_servers.write() = vec![
+			(self_public, "127.0.0.1:1000".parse().unwrap()),
+			(other_public, "127.0.0.1:2000".parse().unwrap()),
+		].into_iter().collect();
+
+		let connect_to = set.key_servers();
+		assert!(!connect_to.contains_key(&self_public), "must never connect to ourselves");
+		assert!(connect_to.contains_key(&other_public));
+		assert_eq!(connect_to.len(), 1);
+	}
+
+	#[test]
+	fn is_local_node_member_reflects_full_membership_set() {
+		let self_public = Public::from_slice(&[1u8; 64]);
+		let other_public = Public::from_slice(&[2u8; 64]);
+
+		let member_set = key_server_set(Some(self_public));
+		*member_set.key_servers.write() = vec![(self_public, "127.0.0.1:1000".parse().unwrap())].into_iter().collect();
+		assert!(member_set.is_local_node_member());
+
+		let non_member_set = key_server_set(Some(self_public));
+		*non_member_set.key_servers.write() = vec![(other_public, "127.0.0.1:2000".parse().unwrap())].into_iter().collect();
+		assert!(!non_member_set.is_local_node_member());
+
+		let no_self_set = key_server_set(None);
+		*no_self_set.key_servers.write() = vec![(other_public, "127.0.0.1:2000".parse().unwrap())].into_iter().collect();
+		assert!(!no_self_set.is_local_node_member());
+	}
+
+	#[test]
+	fn read_key_server_skips_entry_with_malformed_public_key() {
+		let chain = MockChain::new();
+		let contract_addr = Address::from_low_u64_be(0xaced);
+		let id = Address::from_low_u64_be(1);
+
+		let (data, _) = super::key_server_set_contract::functions::get_key_server_public::call(id);
+		// a well-formed public key is 64 bytes - this one is deliberately short
+		chain.set_call_result(contract_addr, data, ethabi::encode(&[Token::Bytes(vec![1, 2, 3])]));
+
+		let set = OnChainKeyServerSet::new(Arc::new(chain), None);
+		assert_eq!(set.read_key_server(contract_addr, id), None);
+	}
+
+	#[test]
+	fn read_key_server_skips_entry_with_malformed_address() {
+		let chain = MockChain::new();
+		let contract_addr = Address::from_low_u64_be(0xaced);
+		let id = Address::from_low_u64_be(1);
+
+		let (data, _) = super::key_server_set_contract::functions::get_key_server_public::call(id);
+		chain.set_call_result(contract_addr, data, ethabi::encode(&[Token::Bytes(vec![0u8; 64])]));
+
+		let (data, _) = super::key_server_set_contract::functions::get_key_server_address::call(id);
+		chain.set_call_result(contract_addr, data, ethabi::encode(&[Token::String("not an ip:port".into())]));
+
+		let set = OnChainKeyServerSet::new(Arc::new(chain), None);
+		assert_eq!(set.read_key_server(contract_addr, id), None);
+	}
+
+	#[test]
+	fn read_key_server_parses_well_formed_entry() {
+		let chain = MockChain::new();
+		let contract_addr = Address::from_low_u64_be(0xaced);
+		let id = Address::from_low_u64_be(1);
+		let public = Public::from_slice(&[7u8; 64]);
+
+		let (data, _) = super::key_server_set_contract::functions::get_key_server_public::call(id);
+		chain.set_call_result(contract_addr, data, ethabi::encode(&[Token::Bytes(public.as_bytes().to_vec())]));
+
+		let (data, _) = super::key_server_set_contract::functions::get_key_server_address::call(id);
+		chain.set_call_result(contract_addr, data, ethabi::encode(&[Token::String("127.0.0.1:8082".into())]));
+
+		let set = OnChainKeyServerSet::new(Arc::new(chain), None);
+		assert_eq!(set.read_key_server(contract_addr, id), Some((public, "127.0.0.1:8082".parse().unwrap())));
+	}
+}